@@ -1,7 +1,7 @@
 //#![no_std]
 
 use core::marker::PhantomData;
-use embedded_hal::serial::{Read, Write};
+use embedded_hal_nb::serial::{Read, Write};
 
 mod private {
     pub trait Sealed {}
@@ -30,9 +30,13 @@ impl Mode for UnInitialized {}
 const START_HEADER_1: u8 = 0x42;
 const START_HEADER_2: u8 = 0x4d;
 
-const MAX_RESPONSE_SIZE: usize = 32;
+// The longest frame we need to hold is a PMS5003ST reading: 4 bytes of header/length,
+// 17 data words (34 bytes) and a 2 byte checksum.
+const MAX_RESPONSE_SIZE: usize = 40;
 
 #[derive(Debug, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SensorData {
     /// PM1.0 concentration in µg/m³, corrected for standard atmosphere
     pub pm10: u16,
@@ -58,10 +62,32 @@ pub struct SensorData {
     pub pm50_count: u16,
     /// Number of >10.0µm particles per 0.1L
     pub pm100_count: u16,
+    /// Formaldehyde (HCHO) concentration in µg/m³, only present on PMS5003ST readings
+    pub hcho: Option<u16>,
+    /// Temperature in 0.1°C, only present on PMS5003T/PMS5003ST readings
+    pub temperature: Option<i16>,
+    /// Relative humidity in 0.1%RH, only present on PMS5003T/PMS5003ST readings
+    pub humidity: Option<u16>,
 }
 
 impl SensorData {
-    pub fn from_raw(raw: &[u8; MAX_RESPONSE_SIZE]) -> Self {
+    /// Decode a frame into sensor data
+    ///
+    /// `length` is the frame length field (the number of bytes following it, including the
+    /// checksum) and is used to detect whether this is a classic PMS7003 frame or a longer
+    /// PMS5003T/PMS5003ST frame carrying environmental data.
+    pub fn from_raw(raw: &[u8; MAX_RESPONSE_SIZE], length: u8) -> Self {
+        let word_count = length.saturating_sub(2) / 2;
+        let has_hcho = word_count >= 16;
+        let has_env = word_count >= 15;
+
+        let hcho = has_hcho.then(|| u16::from_be_bytes([raw[28], raw[29]]));
+        let env_offset = if has_hcho { 30 } else { 28 };
+        let temperature =
+            has_env.then(|| i16::from_be_bytes([raw[env_offset], raw[env_offset + 1]]));
+        let humidity =
+            has_env.then(|| u16::from_be_bytes([raw[env_offset + 2], raw[env_offset + 3]]));
+
         SensorData {
             pm10: u16::from_be_bytes([raw[4], raw[5]]),
             pm25: u16::from_be_bytes([raw[6], raw[7]]),
@@ -75,26 +101,120 @@ impl SensorData {
             pm25_count: u16::from_be_bytes([raw[22], raw[23]]),
             pm50_count: u16::from_be_bytes([raw[24], raw[25]]),
             pm100_count: u16::from_be_bytes([raw[26], raw[27]]),
+            hcho,
+            temperature,
+            humidity,
         }
     }
 }
 
-#[derive(Default)]
+#[cfg(test)]
+mod sensor_data_tests {
+    use super::*;
+
+    // Bytes 4..28: the 12 PM/particle-count words shared by every frame variant, set to
+    // 1..=12 in order (pm10, pm25, pm100, pm10_atmos, pm25_atmos, pm100_atmos, pm03_count,
+    // pm05_count, pm10_count, pm25_count, pm50_count, pm100_count).
+    fn frame_with_common_words() -> [u8; MAX_RESPONSE_SIZE] {
+        let mut raw = [0u8; MAX_RESPONSE_SIZE];
+        for (word, value) in (4..28).step_by(2).zip(1u16..) {
+            let bytes = value.to_be_bytes();
+            raw[word] = bytes[0];
+            raw[word + 1] = bytes[1];
+        }
+        raw
+    }
+
+    fn assert_common_words(data: &SensorData) {
+        assert_eq!(data.pm10, 1);
+        assert_eq!(data.pm25, 2);
+        assert_eq!(data.pm100, 3);
+        assert_eq!(data.pm10_atmos, 4);
+        assert_eq!(data.pm25_atmos, 5);
+        assert_eq!(data.pm100_atmos, 6);
+        assert_eq!(data.pm03_count, 7);
+        assert_eq!(data.pm05_count, 8);
+        assert_eq!(data.pm10_count, 9);
+        assert_eq!(data.pm25_count, 10);
+        assert_eq!(data.pm50_count, 11);
+        assert_eq!(data.pm100_count, 12);
+    }
+
+    #[test]
+    fn decodes_classic_pms7003_frame() {
+        // 13 data words, length = 13 * 2 + 2 = 28
+        let raw = frame_with_common_words();
+
+        let data = SensorData::from_raw(&raw, 28);
+
+        assert_common_words(&data);
+        assert_eq!(data.hcho, None);
+        assert_eq!(data.temperature, None);
+        assert_eq!(data.humidity, None);
+    }
+
+    #[test]
+    fn decodes_pms5003t_frame() {
+        // 13 data words + temperature + humidity, length = 15 * 2 + 2 = 32
+        let mut raw = frame_with_common_words();
+        raw[28..30].copy_from_slice(&250i16.to_be_bytes()); // 25.0 °C
+        raw[30..32].copy_from_slice(&600u16.to_be_bytes()); // 60.0 %RH
+
+        let data = SensorData::from_raw(&raw, 32);
+
+        assert_common_words(&data);
+        assert_eq!(data.hcho, None);
+        assert_eq!(data.temperature, Some(250));
+        assert_eq!(data.humidity, Some(600));
+    }
+
+    #[test]
+    fn decodes_pms5003st_frame() {
+        // 13 data words + HCHO + temperature + humidity, length = 16 * 2 + 2 = 34
+        let mut raw = frame_with_common_words();
+        raw[28..30].copy_from_slice(&120u16.to_be_bytes()); // 120 µg/m³
+        raw[30..32].copy_from_slice(&(-50i16).to_be_bytes()); // -5.0 °C
+        raw[32..34].copy_from_slice(&450u16.to_be_bytes()); // 45.0 %RH
+
+        let data = SensorData::from_raw(&raw, 34);
+
+        assert_common_words(&data);
+        assert_eq!(data.hcho, Some(120));
+        assert_eq!(data.temperature, Some(-50));
+        assert_eq!(data.humidity, Some(450));
+    }
+}
+
 struct SensorReader {
     byte_offset: u8,
     length: u8,
     data: [u8; MAX_RESPONSE_SIZE],
 }
 
-pub struct Pms700X<Error, Serial: Read<u8, Error = Error> + Write<u8, Error = Error>, Mode> {
+impl Default for SensorReader {
+    fn default() -> Self {
+        SensorReader {
+            byte_offset: 0,
+            length: 0,
+            // std's derived `Default` only covers arrays up to length 32
+            data: [0; MAX_RESPONSE_SIZE],
+        }
+    }
+}
+
+pub struct Pms700X<
+    SerialError,
+    Serial: Read<u8, Error = SerialError> + Write<u8, Error = SerialError>,
+    Mode,
+> {
     serial: Serial,
     mode: PhantomData<Mode>,
     command_writer: CommandWriter,
     reader: SensorReader,
 }
 
-impl<Error, Serial: Read<u8, Error = Error> + Write<u8, Error = Error>>
-    Pms700X<Error, Serial, UnInitialized>
+impl<SerialError, Serial: Read<u8, Error = SerialError> + Write<u8, Error = SerialError>>
+    Pms700X<SerialError, Serial, UnInitialized>
 {
     pub fn new(serial: Serial) -> Self {
         Pms700X {
@@ -106,15 +226,18 @@ impl<Error, Serial: Read<u8, Error = Error> + Write<u8, Error = Error>>
     }
 }
 
-impl<Error, Serial: Read<u8, Error = Error> + Write<u8, Error = Error>, SensorMode: Mode>
-    Pms700X<Error, Serial, SensorMode>
+impl<
+        SerialError,
+        Serial: Read<u8, Error = SerialError> + Write<u8, Error = SerialError>,
+        SensorMode: Mode,
+    > Pms700X<SerialError, Serial, SensorMode>
 {
     fn send_command(
         &mut self,
         command: Command,
         data: u16,
         expect_answer: bool,
-    ) -> nb::Result<(), Error> {
+    ) -> nb::Result<(), SerialError> {
         if self.command_writer.command == Command::None {
             self.command_writer = CommandWriter::new(command, data);
         }
@@ -128,7 +251,27 @@ impl<Error, Serial: Read<u8, Error = Error> + Write<u8, Error = Error>, SensorMo
         Ok(())
     }
 
-    fn into_mode<NewMode: Mode>(self) -> Pms700X<Error, Serial, NewMode> {
+    /// Send a command and verify that the sensor's acknowledgement frame echoes back the
+    /// command byte and value we sent, catching a wrong sensor, a baud mismatch or a dropped
+    /// command instead of silently assuming success.
+    fn send_command_checked(
+        &mut self,
+        command: Command,
+        data: u16,
+    ) -> nb::Result<(), Error<SerialError>> {
+        self.send_command(command, data, true)
+            .map_err(map_serial_err)?;
+        if !self.reader.validate_data() {
+            return Err(nb::Error::Other(Error::Checksum));
+        }
+        let value = data.to_le_bytes()[0];
+        if self.reader.data[4] != command as u8 || self.reader.data[5] != value {
+            return Err(nb::Error::Other(Error::UnexpectedFrame));
+        }
+        Ok(())
+    }
+
+    fn into_mode<NewMode: Mode>(self) -> Pms700X<SerialError, Serial, NewMode> {
         Pms700X {
             serial: self.serial,
             mode: PhantomData,
@@ -138,8 +281,10 @@ impl<Error, Serial: Read<u8, Error = Error> + Write<u8, Error = Error>, SensorMo
     }
 
     /// Set the sensor into active mode
-    pub fn into_active(mut self) -> Result<Pms700X<Error, Serial, Active>, Error> {
-        nb::block!(self.send_command(Command::SetMode, 1, true))?;
+    pub fn into_active(
+        mut self,
+    ) -> Result<Pms700X<SerialError, Serial, Active>, Error<SerialError>> {
+        nb::block!(self.send_command_checked(Command::SetMode, 1))?;
         Ok(self.into_mode())
     }
 
@@ -147,8 +292,10 @@ impl<Error, Serial: Read<u8, Error = Error> + Write<u8, Error = Error>, SensorMo
     ///
     /// Note that after setting the sensor into passive mode, you should wait about 30-50ms
     /// before trying to read the sensor data or the sensor will not respond
-    pub fn into_passive(mut self) -> Result<Pms700X<Error, Serial, Passive>, Error> {
-        nb::block!(self.send_command(Command::SetMode, 0, true))?;
+    pub fn into_passive(
+        mut self,
+    ) -> Result<Pms700X<SerialError, Serial, Passive>, Error<SerialError>> {
+        nb::block!(self.send_command_checked(Command::SetMode, 0))?;
         Ok(self.into_mode())
     }
 
@@ -156,84 +303,134 @@ impl<Error, Serial: Read<u8, Error = Error> + Write<u8, Error = Error>, SensorMo
     ///
     /// After waking up the sensor you should wait about 30s before reading the sensor data to wait
     /// for the sensor to stabilize
-    pub fn set_sleeping(
-        &mut self,
-        sleeping: Sleep,
-    ) -> nb::Result<(), <Serial as Write<u8>>::Error> {
-        self.send_command(Command::SetSleep, sleeping as u16, sleeping == Sleep::Sleep)
+    ///
+    /// Note that the sensor only acknowledges the sleep command, not the wakeup command, so
+    /// waking it up cannot be verified the same way.
+    pub fn set_sleeping(&mut self, sleeping: Sleep) -> nb::Result<(), Error<SerialError>> {
+        if sleeping == Sleep::Sleep {
+            self.send_command_checked(Command::SetSleep, sleeping as u16)
+        } else {
+            self.send_command(Command::SetSleep, sleeping as u16, false)
+                .map_err(map_serial_err)
+        }
     }
 }
 
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(u16)]
 pub enum Sleep {
     Sleep = 0,
     Wakeup = 1,
 }
 
-impl<Error, Serial: Read<u8, Error = Error> + Write<u8, Error = Error>>
-    Pms700X<Error, Serial, Active>
+/// Errors returned while reading or configuring the sensor
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Error<SerialError> {
+    /// An error occurred on the underlying serial port
+    Serial(SerialError),
+    /// A frame was received but its checksum didn't match its contents
+    Checksum,
+    /// A frame was received that doesn't match what was expected
+    UnexpectedFrame,
+}
+
+fn map_serial_err<SerialError>(err: nb::Error<SerialError>) -> nb::Error<Error<SerialError>> {
+    match err {
+        nb::Error::WouldBlock => nb::Error::WouldBlock,
+        nb::Error::Other(err) => nb::Error::Other(Error::Serial(err)),
+    }
+}
+
+impl<SerialError, Serial: Read<u8, Error = SerialError> + Write<u8, Error = SerialError>>
+    Pms700X<SerialError, Serial, Active>
 {
-    pub fn read(&mut self) -> nb::Result<SensorData, Error> {
-        self.reader.fill_data(&mut self.serial)?;
+    pub fn read(&mut self) -> nb::Result<SensorData, Error<SerialError>> {
+        self.reader
+            .fill_data(&mut self.serial)
+            .map_err(map_serial_err)?;
         if self.reader.validate_data() {
-            Ok(SensorData::from_raw(&self.reader.data))
+            Ok(SensorData::from_raw(&self.reader.data, self.reader.length))
         } else {
-            Err(nb::Error::WouldBlock)
+            Err(nb::Error::Other(Error::Checksum))
         }
     }
 }
 
-impl<Error, Serial: Read<u8, Error = Error> + Write<u8, Error = Error>>
-    Pms700X<Error, Serial, Passive>
+impl<SerialError, Serial: Read<u8, Error = SerialError> + Write<u8, Error = SerialError>>
+    Pms700X<SerialError, Serial, Passive>
 {
-    pub fn read(&mut self) -> nb::Result<SensorData, Error> {
-        self.send_command(Command::ReadPassive, 0, true)?;
-        Ok(SensorData::from_raw(&self.reader.data))
+    pub fn read(&mut self) -> nb::Result<SensorData, Error<SerialError>> {
+        self.send_command(Command::ReadPassive, 0, true)
+            .map_err(map_serial_err)?;
+        if self.reader.validate_data() {
+            Ok(SensorData::from_raw(&self.reader.data, self.reader.length))
+        } else {
+            Err(nb::Error::Other(Error::Checksum))
+        }
     }
 }
 
 impl SensorReader {
-    fn fill_data<Serial: Read<u8>>(
-        &mut self,
-        serial: &mut Serial,
-    ) -> nb::Result<(), Serial::Error> {
-        let byte = serial.read()?;
+    /// Feed a single byte into the frame parser state machine.
+    ///
+    /// Returns `true` once a full frame, including header, length and checksum, has been
+    /// collected into `self.data`. Shared between the blocking and async readers so the framing
+    /// logic only lives in one place.
+    fn push_byte(&mut self, byte: u8) -> bool {
         let offset = self.byte_offset;
         self.byte_offset += 1;
         self.data[offset as usize] = byte;
         match (offset, byte) {
-            (0, START_HEADER_1) => Err(nb::Error::WouldBlock),
+            (0, START_HEADER_1) => false,
             (0, _) => {
                 // wait until we find the start header
                 self.byte_offset = 0;
-                Err(nb::Error::WouldBlock)
+                false
             }
-            (1, START_HEADER_2) => Err(nb::Error::WouldBlock),
-            (2, 0) => Err(nb::Error::WouldBlock),
+            (1, START_HEADER_2) => false,
+            (2, 0) => false,
             (2, _) => {
-                // we only allow length <= 32
+                // the length high byte must be 0: none of the frames we support are long
+                // enough to need it
                 self.byte_offset = 0;
-                Err(nb::Error::WouldBlock)
+                false
             }
             (3, length_low_byte) => {
                 self.length = length_low_byte;
-                if self.length > MAX_RESPONSE_SIZE as u8 {
+                // the data plus checksum must still fit in our buffer, alongside the
+                // 4 bytes of header and length already written
+                if self.length > (MAX_RESPONSE_SIZE - 4) as u8 {
                     self.byte_offset = 0;
                 }
-                Err(nb::Error::WouldBlock)
+                false
             }
             (offset, _) => {
                 if offset >= self.length + 3 {
                     self.byte_offset = 0;
-                    Ok(())
+                    true
                 } else {
-                    Err(nb::Error::WouldBlock)
+                    false
                 }
             }
         }
     }
 
+    fn fill_data<Serial: Read<u8>>(
+        &mut self,
+        serial: &mut Serial,
+    ) -> nb::Result<(), Serial::Error> {
+        let byte = serial.read()?;
+        if self.push_byte(byte) {
+            Ok(())
+        } else {
+            Err(nb::Error::WouldBlock)
+        }
+    }
+
     fn validate_data(&self) -> bool {
         let mut sum = 0;
         for byte in self.data.iter().take(self.length as usize + 2) {
@@ -292,19 +489,22 @@ impl CommandWriter {
         }
     }
 
+    /// The full 7 byte command frame: header, command, data word and checksum
+    fn frame_bytes(&self) -> [u8; 7] {
+        [
+            START_HEADER_1,
+            START_HEADER_2,
+            self.command as u8,
+            self.data_high,
+            self.data_low,
+            self.verify_high,
+            self.verify_low,
+        ]
+    }
+
     fn write<Serial: Write<u8>>(&mut self, serial: &mut Serial) -> nb::Result<(), Serial::Error> {
         if self.state < 7 {
-            let write_byte = match self.state {
-                0 => START_HEADER_1,
-                1 => START_HEADER_2,
-                2 => self.command as u8,
-                3 => self.data_high,
-                4 => self.data_low,
-                5 => self.verify_high,
-                6 => self.verify_low,
-                _ => unreachable!(),
-            };
-            serial.write(write_byte)?;
+            serial.write(self.frame_bytes()[self.state as usize])?;
 
             self.state += 1;
         }
@@ -316,3 +516,182 @@ impl CommandWriter {
         }
     }
 }
+
+/// Async counterpart of the blocking API, built on `embedded-io-async`
+///
+/// This mirrors the typestate API of the crate root, but is driven by `.await` instead of
+/// `nb::block!`, so it can share a UART with other async embassy drivers.
+#[cfg(feature = "async")]
+pub mod asynch {
+    use super::{
+        Active, Command, CommandWriter, Error, Mode, Passive, SensorData, SensorReader, Sleep,
+        UnInitialized,
+    };
+    use core::marker::PhantomData;
+    use embedded_io_async::{Read, Write};
+
+    pub struct Pms700X<Serial: Read + Write, Mode> {
+        serial: Serial,
+        mode: PhantomData<Mode>,
+        command_writer: CommandWriter,
+        reader: SensorReader,
+    }
+
+    impl<Serial: Read + Write> Pms700X<Serial, UnInitialized> {
+        pub fn new(serial: Serial) -> Self {
+            Pms700X {
+                serial,
+                mode: PhantomData,
+                command_writer: CommandWriter::default(),
+                reader: SensorReader::default(),
+            }
+        }
+    }
+
+    impl<Serial: Read + Write, SensorMode: Mode> Pms700X<Serial, SensorMode> {
+        async fn send_command(
+            &mut self,
+            command: Command,
+            data: u16,
+            expect_answer: bool,
+        ) -> Result<(), Serial::Error> {
+            if self.command_writer.command == Command::None {
+                self.command_writer = CommandWriter::new(command, data);
+            }
+
+            self.command_writer.write_async(&mut self.serial).await?;
+            self.serial.flush().await?;
+            if expect_answer {
+                self.reader.fill_data_async(&mut self.serial).await?;
+            }
+            self.command_writer.command = Command::None;
+            Ok(())
+        }
+
+        /// Send a command and verify that the sensor's acknowledgement frame echoes back the
+        /// command byte and value we sent, catching a wrong sensor, a baud mismatch or a dropped
+        /// command instead of silently assuming success.
+        async fn send_command_checked(
+            &mut self,
+            command: Command,
+            data: u16,
+        ) -> Result<(), Error<Serial::Error>> {
+            self.send_command(command, data, true)
+                .await
+                .map_err(Error::Serial)?;
+            if !self.reader.validate_data() {
+                return Err(Error::Checksum);
+            }
+            let value = data.to_le_bytes()[0];
+            if self.reader.data[4] != command as u8 || self.reader.data[5] != value {
+                return Err(Error::UnexpectedFrame);
+            }
+            Ok(())
+        }
+
+        fn into_mode<NewMode: Mode>(self) -> Pms700X<Serial, NewMode> {
+            Pms700X {
+                serial: self.serial,
+                mode: PhantomData,
+                command_writer: self.command_writer,
+                reader: self.reader,
+            }
+        }
+
+        /// Set the sensor into active mode
+        pub async fn into_active(
+            mut self,
+        ) -> Result<Pms700X<Serial, Active>, Error<Serial::Error>> {
+            self.send_command_checked(Command::SetMode, 1).await?;
+            Ok(self.into_mode())
+        }
+
+        /// Set the sensor into passive mode
+        ///
+        /// Note that after setting the sensor into passive mode, you should wait about 30-50ms
+        /// before trying to read the sensor data or the sensor will not respond
+        pub async fn into_passive(
+            mut self,
+        ) -> Result<Pms700X<Serial, Passive>, Error<Serial::Error>> {
+            self.send_command_checked(Command::SetMode, 0).await?;
+            Ok(self.into_mode())
+        }
+
+        /// Set the sensor to sleep or wake it up
+        ///
+        /// After waking up the sensor you should wait about 30s before reading the sensor data to wait
+        /// for the sensor to stabilize
+        ///
+        /// Note that the sensor only acknowledges the sleep command, not the wakeup command, so
+        /// waking it up cannot be verified the same way.
+        pub async fn set_sleeping(&mut self, sleeping: Sleep) -> Result<(), Error<Serial::Error>> {
+            if sleeping == Sleep::Sleep {
+                self.send_command_checked(Command::SetSleep, sleeping as u16)
+                    .await
+            } else {
+                self.send_command(Command::SetSleep, sleeping as u16, false)
+                    .await
+                    .map_err(Error::Serial)
+            }
+        }
+    }
+
+    impl<Serial: Read + Write> Pms700X<Serial, Active> {
+        pub async fn read(&mut self) -> Result<SensorData, Error<Serial::Error>> {
+            self.reader
+                .fill_data_async(&mut self.serial)
+                .await
+                .map_err(Error::Serial)?;
+            if self.reader.validate_data() {
+                Ok(SensorData::from_raw(&self.reader.data, self.reader.length))
+            } else {
+                Err(Error::Checksum)
+            }
+        }
+    }
+
+    impl<Serial: Read + Write> Pms700X<Serial, Passive> {
+        pub async fn read(&mut self) -> Result<SensorData, Error<Serial::Error>> {
+            self.send_command(Command::ReadPassive, 0, true)
+                .await
+                .map_err(Error::Serial)?;
+            if self.reader.validate_data() {
+                Ok(SensorData::from_raw(&self.reader.data, self.reader.length))
+            } else {
+                Err(Error::Checksum)
+            }
+        }
+    }
+
+    impl SensorReader {
+        async fn fill_data_async<Serial: Read>(
+            &mut self,
+            serial: &mut Serial,
+        ) -> Result<(), Serial::Error> {
+            loop {
+                let mut byte = [0u8; 1];
+                let read = serial.read(&mut byte).await?;
+                if read == 0 {
+                    continue;
+                }
+                if self.push_byte(byte[0]) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    impl CommandWriter {
+        async fn write_async<Serial: Write>(
+            &mut self,
+            serial: &mut Serial,
+        ) -> Result<(), Serial::Error> {
+            let frame = self.frame_bytes();
+            let mut written = 0;
+            while written < frame.len() {
+                written += serial.write(&frame[written..]).await?;
+            }
+            Ok(())
+        }
+    }
+}